@@ -1,6 +1,6 @@
-use anchor_lang::AccountDeserialize;
 use anchor_lang::__private::bytemuck::Zeroable;
 use anchor_lang::prelude::*;
+use anchor_lang::AccountDeserialize;
 use anchor_spl::token::Token;
 use anyhow::Result;
 use fixed::types::I80F48;
@@ -17,12 +17,46 @@ use crate::{
 };
 use jupiter_amm_interface::{
     AccountMap, Amm, KeyedAccount, Quote, QuoteParams, Side as JupiterSide, Swap,
-    SwapAndAccountMetas, SwapParams,
+    SwapAndAccountMetas, SwapMode, SwapParams,
 };
 /// An abstraction in order to share reserve mints and necessary data
-use solana_sdk::{pubkey::Pubkey, sysvar::clock};
+use solana_sdk::{
+    account::{Account, AccountSharedData, ReadableAccount},
+    pubkey::Pubkey,
+    sysvar::clock,
+};
 use std::cell::RefCell;
 
+/// A read-only view over an on-chain account's owner and data, abstracting over whether the
+/// caller holds a fully materialized [`Account`] (e.g. an `AccountMap` from a one-shot RPC
+/// fetch) or an [`AccountSharedData`] (e.g. a geyser/websocket feed holding the latest version
+/// of each account in place). [`OpenBookMarket::update_account`] is generic over this so it can
+/// be fed either source without cloning into the other.
+pub trait AccountReader {
+    fn owner(&self) -> &Pubkey;
+    fn data(&self) -> &[u8];
+}
+
+impl AccountReader for Account {
+    fn owner(&self) -> &Pubkey {
+        &self.owner
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl AccountReader for AccountSharedData {
+    fn owner(&self) -> &Pubkey {
+        ReadableAccount::owner(self)
+    }
+
+    fn data(&self) -> &[u8] {
+        ReadableAccount::data(self)
+    }
+}
+
 #[derive(Clone)]
 pub struct OpenBookMarket {
     market: Market,
@@ -35,6 +69,506 @@ pub struct OpenBookMarket {
     related_accounts: Vec<Pubkey>,
     reserve_mints: [Pubkey; 2],
     oracle_price: Option<I80F48>,
+    /// Clock slot at which an oracle account's data was last seen to change, used to detect an
+    /// oracle that has stopped publishing even though the rest of the market keeps updating.
+    /// Recomputes driven only by the clock leave it untouched.
+    oracle_price_slot: Option<u64>,
+    /// Most recent clock slot seen across any account update, not just oracle-affecting ones.
+    slot: u64,
+    /// Raw oracle accounts cached so a streamed update to just one of them (or to the clock)
+    /// can still re-derive `oracle_price`, which needs both at once.
+    oracle_a_account: Option<Account>,
+    oracle_b_account: Option<Account>,
+}
+
+/// Reverse counterpart of [`amounts_from_book`] for exact-out quotes.
+///
+/// Instead of spending up to `max_base_lots`/`max_quote_lots_including_fees` and reporting
+/// whatever comes out, this walks the *opposite* book (the one the taker would actually fill
+/// against) until `target_out_native` of the output token has been produced, then reports the
+/// input required to get there. The final level touched is almost always a partial fill, so its
+/// lots are pro-rated and the resulting input is rounded up so the quoted output is never short.
+fn amounts_from_book_exact_out(
+    book: Orderbook,
+    side: Side,
+    target_out_native: i64,
+    market: &Market,
+    oracle_price: Option<I80F48>,
+    now_ts: u64,
+) -> Result<Amounts> {
+    Ok(match side {
+        // Bid: taker pays quote and wants `target_out_native` base out, so we walk the asks.
+        Side::Bid => walk_exact_out_bid(
+            book.asks
+                .iter_valid(now_ts, oracle_price)
+                .map(|level| (level.price_lots, level.node.quantity)),
+            target_out_native,
+            market,
+        ),
+        // Ask: taker pays base and wants `target_out_native` quote (net of fee) out, so we walk the bids.
+        Side::Ask => walk_exact_out_ask(
+            book.bids
+                .iter_valid(now_ts, oracle_price)
+                .map(|level| (level.price_lots, level.node.quantity)),
+            target_out_native,
+            market,
+        ),
+    })
+}
+
+/// Core walk for [`amounts_from_book_exact_out`]'s `Side::Bid` branch, over plain
+/// `(price_lots, quantity)` pairs instead of a real zero-copy `BookSide`, so it can be exercised
+/// with synthetic book data in tests.
+fn walk_exact_out_bid(
+    levels: impl Iterator<Item = (i64, i64)>,
+    target_out_native: i64,
+    market: &Market,
+) -> Amounts {
+    let mut total_base_taken_native: i64 = 0;
+    let mut total_quote_taken_native: i64 = 0;
+    let mut not_enough_liquidity = false;
+
+    for (price_lots, quantity) in levels {
+        if total_base_taken_native >= target_out_native {
+            break;
+        }
+
+        let level_base_native = quantity * market.base_lot_size;
+        let remaining_base_native = target_out_native - total_base_taken_native;
+        let base_lots_needed = div_ceil(
+            remaining_base_native.min(level_base_native),
+            market.base_lot_size,
+        );
+
+        total_base_taken_native += base_lots_needed * market.base_lot_size;
+        total_quote_taken_native += base_lots_needed * price_lots * market.quote_lot_size;
+    }
+
+    if total_base_taken_native < target_out_native {
+        not_enough_liquidity = true;
+    }
+
+    // Fee is charged on top of the quote the taker pays in, so it inflates in_amount.
+    let fee = ceil_fee(market.taker_fee, total_quote_taken_native);
+    total_quote_taken_native += fee;
+
+    Amounts {
+        total_base_taken_native,
+        total_quote_taken_native,
+        fee,
+        not_enough_liquidity,
+    }
+}
+
+/// Core walk for [`amounts_from_book_exact_out`]'s `Side::Ask` branch, over plain
+/// `(price_lots, quantity)` pairs instead of a real zero-copy `BookSide`, so it can be exercised
+/// with synthetic book data in tests.
+fn walk_exact_out_ask(
+    levels: impl Iterator<Item = (i64, i64)>,
+    target_out_native: i64,
+    market: &Market,
+) -> Amounts {
+    let mut total_base_taken_native: i64 = 0;
+    let mut total_quote_taken_native: i64 = 0;
+    let mut not_enough_liquidity = false;
+
+    // Gross the target up first: the fee is taken out of the quote the maker side pays, so we
+    // need to walk the book until the *pre-fee* quote covers the gross amount that nets down to
+    // `target_out_native` once the fee comes out, i.e. solve `target = gross * (1 - taker_fee)`
+    // for `gross`, rather than just adding fee(target).
+    let target_quote_gross = gross_up(market.taker_fee, target_out_native);
+
+    for (price_lots, quantity) in levels {
+        if total_quote_taken_native >= target_quote_gross {
+            break;
+        }
+
+        let level_quote_native = quantity * price_lots * market.quote_lot_size;
+        let remaining_quote_native = target_quote_gross - total_quote_taken_native;
+        let quote_native_needed = remaining_quote_native.min(level_quote_native);
+        let base_lots_needed = div_ceil(quote_native_needed, price_lots * market.quote_lot_size);
+
+        total_base_taken_native += base_lots_needed * market.base_lot_size;
+        total_quote_taken_native += base_lots_needed * price_lots * market.quote_lot_size;
+    }
+
+    if total_quote_taken_native < target_quote_gross {
+        not_enough_liquidity = true;
+    }
+
+    // Mirror the Bid branch's `total_quote_taken_native += fee`: `amounts_from_book`'s
+    // convention for Ask is `total_quote_taken_native == matched - fee`, so the shared
+    // `out = total_quote_taken_native + fee` mapping recovers the matched amount instead of
+    // over-promising one fee's worth of extra output.
+    let fee = ceil_fee(market.taker_fee, total_quote_taken_native);
+    total_quote_taken_native -= fee;
+
+    Amounts {
+        total_base_taken_native,
+        total_quote_taken_native,
+        fee,
+        not_enough_liquidity,
+    }
+}
+
+/// Counts the maker **orders** (not deduped accounts) that matching against `book_side` up to
+/// `max_base_lots`/`max_quote_lots_including_fees` would cross. This is what the event heap
+/// actually has to hold room for: a `PlaceTakeOrder` emits one `FillEvent` per crossed order, and
+/// `remaining_accounts_to_crank` instead returns the deduped set of maker *accounts*, which
+/// under-counts whenever one account rests more than one crossed order.
+fn crossed_order_count(
+    book_side: impl Iterator<Item = (i64, i64)>,
+    max_base_lots: i64,
+    max_quote_lots_including_fees: i64,
+) -> usize {
+    let mut base_lots_left = max_base_lots;
+    let mut quote_lots_left = max_quote_lots_including_fees;
+    let mut count = 0;
+
+    for (price_lots, quantity) in book_side {
+        if base_lots_left <= 0 || quote_lots_left <= 0 {
+            break;
+        }
+
+        let match_lots = base_lots_left
+            .min(quantity)
+            .min(quote_lots_left / price_lots);
+        if match_lots <= 0 {
+            break;
+        }
+
+        base_lots_left -= match_lots;
+        quote_lots_left -= match_lots * price_lots;
+        count += 1;
+    }
+
+    count
+}
+
+fn div_ceil(numerator: i64, denominator: i64) -> i64 {
+    (numerator + denominator - 1) / denominator
+}
+
+fn ceil_fee(taker_fee: I80F48, quote_native: i64) -> i64 {
+    taker_fee
+        .checked_mul_int(quote_native.into())
+        .and_then(|fee| fee.checked_ceil())
+        .and_then(|fee| fee.checked_to_num())
+        .unwrap_or(0)
+}
+
+/// Inverse of charging `taker_fee` on a gross amount: the smallest `gross` such that
+/// `gross - ceil_fee(taker_fee, gross) >= net`, i.e. `gross = net / (1 - taker_fee)` rounded up.
+fn gross_up(taker_fee: I80F48, net_native: i64) -> i64 {
+    I80F48::checked_from_num(net_native)
+        .and_then(|net| {
+            let one = I80F48::from_num(1);
+            net.checked_div(one.checked_sub(taker_fee)?)
+        })
+        .and_then(|gross| gross.checked_ceil())
+        .and_then(|gross| gross.checked_to_num())
+        .unwrap_or(net_native)
+}
+
+impl OpenBookMarket {
+    /// Deserializes a single account that changed and folds it into the market state, without
+    /// touching anything else. `key` identifies which related account `reader` holds; unknown
+    /// keys are ignored so callers can pass through every account from a subscription without
+    /// filtering first. Oracle and clock updates both trigger a fresh `oracle_price`, since
+    /// either one moving can change it.
+    pub fn update_account(&mut self, key: &Pubkey, reader: &impl AccountReader) -> Result<()> {
+        if *key == self.market.bids {
+            self.bids = BookSide::try_deserialize(&mut reader.data()).unwrap();
+        } else if *key == self.market.asks {
+            self.asks = BookSide::try_deserialize(&mut reader.data()).unwrap();
+        } else if *key == self.market.event_heap {
+            self.event_heap = EventHeap::try_deserialize(&mut reader.data()).unwrap();
+        } else if *key == clock::ID {
+            let clock: Clock = bincode::deserialize(reader.data())?;
+            self.slot = clock.slot;
+            // A configured oracle whose account hasn't streamed in yet must not be treated as
+            // "no oracle" by recomputing now; wait until both needed accounts are cached.
+            if self.oracle_accounts_ready() {
+                self.recompute_oracle_price(clock.slot)?;
+            }
+        } else if Option::<Pubkey>::from(self.market.oracle_a) == Some(*key) {
+            let data = reader.data().to_vec();
+            // A standard polling integration re-supplies every related account, oracle included,
+            // on each tick, so "we were handed an oracle account" happens every poll regardless
+            // of whether the oracle actually published anything new. Only bytes actually
+            // changing counts as a fresh observation for staleness purposes.
+            let data_changed = self
+                .oracle_a_account
+                .as_ref()
+                .is_none_or(|account| account.data != data);
+            self.oracle_a_account = Some(Account {
+                owner: *reader.owner(),
+                data,
+                ..Account::default()
+            });
+            if self.oracle_accounts_ready() {
+                self.recompute_oracle_price(self.slot)?;
+                if data_changed {
+                    self.oracle_price_slot = self.oracle_price.map(|_| self.slot);
+                }
+            }
+        } else if Option::<Pubkey>::from(self.market.oracle_b) == Some(*key) {
+            let data = reader.data().to_vec();
+            let data_changed = self
+                .oracle_b_account
+                .as_ref()
+                .is_none_or(|account| account.data != data);
+            self.oracle_b_account = Some(Account {
+                owner: *reader.owner(),
+                data,
+                ..Account::default()
+            });
+            if self.oracle_accounts_ready() {
+                self.recompute_oracle_price(self.slot)?;
+                if data_changed {
+                    self.oracle_price_slot = self.oracle_price.map(|_| self.slot);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether every oracle account the market is configured with has already been cached, so
+    /// `oracle_price` can be (re)computed without mistaking "not streamed in yet" for "market
+    /// has no oracle".
+    fn oracle_accounts_ready(&self) -> bool {
+        let needs_a = Option::<Pubkey>::from(self.market.oracle_a).is_some();
+        let needs_b = Option::<Pubkey>::from(self.market.oracle_b).is_some();
+
+        (!needs_a || self.oracle_a_account.is_some())
+            && (!needs_b || self.oracle_b_account.is_some())
+    }
+
+    fn recompute_oracle_price(&mut self, slot: u64) -> Result<()> {
+        let oracle_acc = |account: &Option<Account>,
+                          nonzero_pubkey: NonZeroPubkeyOption|
+         -> Option<accounts_zerocopy::KeyedAccount> {
+            let key = Option::from(nonzero_pubkey)?;
+            let account = account.clone()?;
+            Some(accounts_zerocopy::KeyedAccount { key, account })
+        };
+
+        self.oracle_price = self.market.oracle_price(
+            oracle_acc(&self.oracle_a_account, self.market.oracle_a).as_ref(),
+            oracle_acc(&self.oracle_b_account, self.market.oracle_b).as_ref(),
+            slot,
+        )?;
+
+        Ok(())
+    }
+
+    /// The oracle price to use for pricing oracle-pegged levels, or `None` if there isn't one
+    /// configured *or* the one we have is older than the market's staleness window allows.
+    /// Treating a stale oracle the same way as a missing one means pegged levels are simply
+    /// skipped rather than priced off a dead feed. `oracle_price_slot` tracks the slot the
+    /// oracle *data* was last observed, not the slot of the last recompute, so a clock ticking
+    /// forward on its own can't keep a dead feed looking fresh.
+    fn fresh_oracle_price(&self) -> Option<I80F48> {
+        let oracle_price = self.oracle_price?;
+        let oracle_price_slot = self.oracle_price_slot?;
+
+        let max_staleness_slots = self.market.oracle_config.max_staleness_slots;
+        if max_staleness_slots >= 0
+            && self.slot.saturating_sub(oracle_price_slot) > max_staleness_slots as u64
+        {
+            return None;
+        }
+
+        Some(oracle_price)
+    }
+
+    /// Aggregated L2 view of the in-memory book: one price-sorted, best-first vector of levels
+    /// per side, with sizes summed across every order resting at the same UI price. Spares
+    /// integrators from re-implementing the lot-to-UI conversion and oracle-peg resolution
+    /// themselves.
+    pub fn get_orderbook_levels(&self, depth: usize) -> OrderbookSnapshot {
+        let oracle_price = self.fresh_oracle_price();
+
+        OrderbookSnapshot {
+            bids: self.aggregated_levels(&self.bids, oracle_price, depth),
+            asks: self.aggregated_levels(&self.asks, oracle_price, depth),
+        }
+    }
+
+    fn aggregated_levels(
+        &self,
+        book_side: &BookSide,
+        oracle_price: Option<I80F48>,
+        depth: usize,
+    ) -> Vec<OrderbookLevel> {
+        // Aggregate by the integer `price_lots` key, not the UI price, and convert to UI units
+        // only once per merged level: two orders resolving to the same UI price (e.g. a
+        // fixed-price order and an oracle-pegged order) aren't guaranteed to produce
+        // bit-identical floats, so comparing converted prices would be a float-equality trap.
+        let mut levels: Vec<(i64, f64)> = Vec::new();
+
+        for item in book_side.iter_valid(self.timestamp, oracle_price) {
+            let size = self.base_lots_to_ui(item.node.quantity);
+
+            merge_level(&mut levels, item.price_lots, size);
+        }
+
+        levels
+            .into_iter()
+            .take(depth)
+            .map(|(price_lots, size)| OrderbookLevel {
+                price: self.price_lots_to_ui(price_lots),
+                size,
+            })
+            .collect()
+    }
+
+    fn price_lots_to_ui(&self, price_lots: i64) -> f64 {
+        price_lots as f64 * self.market.quote_lot_size as f64 / self.market.base_lot_size as f64
+            * 10f64.powi(self.market.base_decimals as i32 - self.market.quote_decimals as i32)
+    }
+
+    fn base_lots_to_ui(&self, base_lots: i64) -> f64 {
+        base_lots as f64 * self.market.base_lot_size as f64
+            / 10f64.powi(self.market.base_decimals as i32)
+    }
+
+    /// Free slots left in the event heap. Crossing a maker order on a `PlaceTakeOrder` emits
+    /// one fill event, and a heap with no room left for those events causes the take to fail or
+    /// only partially fill on-chain.
+    fn event_heap_free_slots(&self) -> usize {
+        self.event_heap
+            .capacity()
+            .saturating_sub(self.event_heap.len())
+    }
+
+    /// Shrinks `(max_base_lots, max_quote_lots_including_fees)` to the largest size whose crossed
+    /// order count (from [`crossed_order_count`]) still fits in the event heap's remaining
+    /// capacity, budgeted against `event_heap_free_slots` plus the maker accounts this same
+    /// instruction's [`remaining_accounts_to_crank`] set would clear out first. That crankable
+    /// set is a property of the resting book, not of how big this take ends up, so it's computed
+    /// once at the uncapped request rather than re-derived at every candidate size below.
+    /// Crossed makers aren't uniformly sized, so this searches the real crossed-order count at
+    /// candidate sizes rather than scaling linearly by a maker-count ratio. `quote` and
+    /// `get_swap_and_account_metas` both call this, so the quoted amounts and the instruction
+    /// built from them can never disagree on the executable size. Returns the (possibly
+    /// untouched) lot amounts plus whether a cap was applied.
+    fn cap_lots_to_event_heap_capacity(
+        &self,
+        side: Side,
+        max_base_lots: i64,
+        max_quote_lots_including_fees: i64,
+    ) -> Result<(i64, i64, bool)> {
+        let crankable_len = {
+            let bids_ref = RefCell::new(self.bids);
+            let asks_ref = RefCell::new(self.asks);
+            let book = Orderbook {
+                bids: bids_ref.borrow_mut(),
+                asks: asks_ref.borrow_mut(),
+            };
+
+            remaining_accounts_to_crank(
+                book,
+                side,
+                max_base_lots,
+                max_quote_lots_including_fees,
+                &self.market,
+                self.fresh_oracle_price(),
+                self.timestamp,
+            )?
+            .len()
+        };
+        let budget = self.event_heap_free_slots() + crankable_len;
+
+        let crossed_orders = |base_lots: i64, quote_lots: i64| -> usize {
+            let oracle_price = self.fresh_oracle_price();
+            match side {
+                Side::Bid => crossed_order_count(
+                    self.asks
+                        .iter_valid(self.timestamp, oracle_price)
+                        .map(|level| (level.price_lots, level.node.quantity)),
+                    base_lots,
+                    quote_lots,
+                ),
+                Side::Ask => crossed_order_count(
+                    self.bids
+                        .iter_valid(self.timestamp, oracle_price)
+                        .map(|level| (level.price_lots, level.node.quantity)),
+                    base_lots,
+                    quote_lots,
+                ),
+            }
+        };
+
+        if crossed_orders(max_base_lots, max_quote_lots_including_fees) <= budget {
+            return Ok((max_base_lots, max_quote_lots_including_fees, false));
+        }
+
+        if budget == 0 {
+            return Ok((0, 0, true));
+        }
+
+        // Binary search one side's lot count (quote lots for a bid, base lots for an ask) for the
+        // largest size whose crossed order count still fits, holding the other fixed. On exact-in
+        // the other is an exchange-imposed ceiling such as `max_quote_lots()`; on exact-out both
+        // come from the book walk, and shrinking the searched one alone still bounds the match.
+        match side {
+            Side::Bid => {
+                let mut lo: i64 = 0;
+                let mut hi = max_quote_lots_including_fees;
+                while lo < hi {
+                    let mid = lo + (hi - lo + 1) / 2;
+                    if crossed_orders(max_base_lots, mid) <= budget {
+                        lo = mid;
+                    } else {
+                        hi = mid - 1;
+                    }
+                }
+                Ok((max_base_lots, lo, true))
+            }
+            Side::Ask => {
+                let mut lo: i64 = 0;
+                let mut hi = max_base_lots;
+                while lo < hi {
+                    let mid = lo + (hi - lo + 1) / 2;
+                    if crossed_orders(mid, max_quote_lots_including_fees) <= budget {
+                        lo = mid;
+                    } else {
+                        hi = mid - 1;
+                    }
+                }
+                Ok((lo, max_quote_lots_including_fees, true))
+            }
+        }
+    }
+}
+
+/// One aggregated price level of an [`OrderbookSnapshot`], in UI units.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrderbookLevel {
+    pub price: f64,
+    pub size: f64,
+}
+
+/// Aggregated L2 depth snapshot returned by [`OpenBookMarket::get_orderbook_levels`].
+#[derive(Debug, Clone, Default)]
+pub struct OrderbookSnapshot {
+    pub bids: Vec<OrderbookLevel>,
+    pub asks: Vec<OrderbookLevel>,
+}
+
+/// Folds one book order into `levels`, summing its size into whichever existing level already
+/// has the same `price_lots` key, wherever that level is in the vector. A fixed-price order and
+/// an oracle-pegged order can resolve to the same price without landing adjacently in book
+/// iteration order, so matching only `levels.last_mut()` would miss that case and push a
+/// duplicate level instead of summing into it. Keying on the integer lots (rather than a UI
+/// price converted to `f64`) also sidesteps float-equality comparison entirely.
+fn merge_level(levels: &mut Vec<(i64, f64)>, price_lots: i64, size: f64) {
+    match levels.iter_mut().find(|(lots, _)| *lots == price_lots) {
+        Some((_, existing_size)) => *existing_size += size,
+        None => levels.push((price_lots, size)),
+    }
 }
 
 impl Amm for OpenBookMarket {
@@ -85,35 +619,24 @@ impl Amm for OpenBookMarket {
             bids: BookSide::zeroed(),
             asks: BookSide::zeroed(),
             oracle_price: None,
+            oracle_price_slot: None,
+            slot: 0,
             timestamp: 0,
+            oracle_a_account: None,
+            oracle_b_account: None,
         })
     }
 
+    /// Re-derives the full market state from a freshly fetched set of accounts. Thin wrapper
+    /// around [`OpenBookMarket::update_account`] for callers (like a one-shot RPC poll) that
+    /// always have every related account on hand at once; streaming callers should prefer
+    /// `update_account` directly so a single changed account doesn't require re-fetching the
+    /// rest.
     fn update(&mut self, account_map: &AccountMap) -> Result<()> {
-        let bids_data = account_map.get(&self.market.bids).unwrap();
-        self.bids = BookSide::try_deserialize(&mut bids_data.data.as_slice()).unwrap();
-
-        let asks_data = account_map.get(&self.market.asks).unwrap();
-        self.asks = BookSide::try_deserialize(&mut asks_data.data.as_slice()).unwrap();
-
-        let event_heap_data = account_map.get(&self.market.event_heap).unwrap();
-        self.event_heap = EventHeap::try_deserialize(&mut event_heap_data.data.as_slice()).unwrap();
-
-        let clock_data = account_map.get(&clock::ID).unwrap();
-        let clock: Clock = bincode::deserialize(clock_data.data.as_slice())?;
-
-        let oracle_acc =
-            |nonzero_pubkey: NonZeroPubkeyOption| -> Option<accounts_zerocopy::KeyedAccount> {
-                let key = Option::from(nonzero_pubkey)?;
-                let account = account_map.get(&key).unwrap().clone();
-                Some(accounts_zerocopy::KeyedAccount { key, account })
-            };
-
-        self.oracle_price = self.market.oracle_price(
-            oracle_acc(self.market.oracle_a).as_ref(),
-            oracle_acc(self.market.oracle_b).as_ref(),
-            clock.slot,
-        )?;
+        for key in self.get_accounts_to_update() {
+            let account = account_map.get(&key).unwrap();
+            self.update_account(&key, account)?;
+        }
 
         Ok(())
     }
@@ -125,21 +648,7 @@ impl Amm for OpenBookMarket {
             Side::Ask
         };
 
-        let input_amount = i64::try_from(quote_params.in_amount)?;
-
-        // quote params can have exact in (which is implemented here) and exact out which is not implemented
-        // check with jupiter to add to their API exact_out support
-        let (max_base_lots, max_quote_lots_including_fees) = match side {
-            Side::Bid => (
-                self.market.max_base_lots(),
-                input_amount / self.market.quote_lot_size
-                    + input_amount % self.market.quote_lot_size,
-            ),
-            Side::Ask => (
-                input_amount / self.market.base_lot_size,
-                self.market.max_quote_lots(),
-            ),
-        };
+        let amount = i64::try_from(quote_params.in_amount)?;
 
         let bids_ref = RefCell::new(self.bids);
         let asks_ref = RefCell::new(self.asks);
@@ -148,15 +657,75 @@ impl Amm for OpenBookMarket {
             asks: asks_ref.borrow_mut(),
         };
 
-        let order_amounts: Amounts = amounts_from_book(
-            book,
-            side,
-            max_base_lots,
-            max_quote_lots_including_fees,
-            &self.market,
-            self.oracle_price,
-            self.timestamp,
-        )?;
+        let (mut order_amounts, max_base_lots, max_quote_lots_including_fees): (Amounts, i64, i64) =
+            match quote_params.swap_mode {
+                SwapMode::ExactIn => {
+                    let (max_base_lots, max_quote_lots_including_fees) = match side {
+                        Side::Bid => (
+                            self.market.max_base_lots(),
+                            amount / self.market.quote_lot_size
+                                + amount % self.market.quote_lot_size,
+                        ),
+                        Side::Ask => (
+                            amount / self.market.base_lot_size,
+                            self.market.max_quote_lots(),
+                        ),
+                    };
+
+                    let amounts = amounts_from_book(
+                        book,
+                        side,
+                        max_base_lots,
+                        max_quote_lots_including_fees,
+                        &self.market,
+                        self.fresh_oracle_price(),
+                        self.timestamp,
+                    )?;
+
+                    (amounts, max_base_lots, max_quote_lots_including_fees)
+                }
+                SwapMode::ExactOut => {
+                    let amounts = amounts_from_book_exact_out(
+                        book,
+                        side,
+                        amount,
+                        &self.market,
+                        self.fresh_oracle_price(),
+                        self.timestamp,
+                    )?;
+
+                    let max_base_lots =
+                        div_ceil(amounts.total_base_taken_native, self.market.base_lot_size);
+                    let max_quote_lots_including_fees =
+                        div_ceil(amounts.total_quote_taken_native, self.market.quote_lot_size);
+
+                    (amounts, max_base_lots, max_quote_lots_including_fees)
+                }
+            };
+
+        let (capped_base_lots, capped_quote_lots, capacity_capped) = self
+            .cap_lots_to_event_heap_capacity(side, max_base_lots, max_quote_lots_including_fees)?;
+
+        if capacity_capped {
+            let bids_ref = RefCell::new(self.bids);
+            let asks_ref = RefCell::new(self.asks);
+            let book = Orderbook {
+                bids: bids_ref.borrow_mut(),
+                asks: asks_ref.borrow_mut(),
+            };
+
+            // Re-walk the book at the capped size instead of scaling the previous amounts:
+            // scaling assumes every crossed maker is the same size, which isn't true in general.
+            order_amounts = amounts_from_book(
+                book,
+                side,
+                capped_base_lots,
+                capped_quote_lots,
+                &self.market,
+                self.fresh_oracle_price(),
+                self.timestamp,
+            )?;
+        }
 
         let (in_amount, out_amount) = match side {
             Side::Bid => (
@@ -174,7 +743,7 @@ impl Amm for OpenBookMarket {
             out_amount,
             fee_mint: self.market.quote_mint,
             fee_amount: order_amounts.fee,
-            not_enough_liquidity: order_amounts.not_enough_liquidity,
+            not_enough_liquidity: order_amounts.not_enough_liquidity || capacity_capped,
             ..Quote::default()
         })
     }
@@ -182,6 +751,8 @@ impl Amm for OpenBookMarket {
     fn get_swap_and_account_metas(&self, swap_params: &SwapParams) -> Result<SwapAndAccountMetas> {
         let SwapParams {
             in_amount,
+            out_amount,
+            swap_mode,
             source_mint,
             user_destination_token_account,
             user_source_token_account,
@@ -224,20 +795,60 @@ impl Amm for OpenBookMarket {
 
         let mut account_metas = accounts.to_account_metas(None);
 
-        let input_amount = i64::try_from(*in_amount)?;
+        let (max_base_lots, max_quote_lots_including_fees) = match swap_mode {
+            SwapMode::ExactIn => {
+                let input_amount = i64::try_from(*in_amount)?;
+                match side {
+                    Side::Bid => (
+                        self.market.max_base_lots(),
+                        input_amount / self.market.quote_lot_size
+                            + input_amount % self.market.quote_lot_size,
+                    ),
+                    Side::Ask => (
+                        input_amount / self.market.base_lot_size,
+                        self.market.max_quote_lots(),
+                    ),
+                }
+            }
+            SwapMode::ExactOut => {
+                let target_out = i64::try_from(*out_amount)?;
 
-        let (max_base_lots, max_quote_lots_including_fees) = match side {
-            Side::Bid => (
-                self.market.max_base_lots(),
-                input_amount / self.market.quote_lot_size
-                    + input_amount % self.market.quote_lot_size,
-            ),
-            Side::Ask => (
-                input_amount / self.market.base_lot_size,
-                self.market.max_quote_lots(),
-            ),
+                let bids_ref = RefCell::new(self.bids);
+                let asks_ref = RefCell::new(self.asks);
+                let book = Orderbook {
+                    bids: bids_ref.borrow_mut(),
+                    asks: asks_ref.borrow_mut(),
+                };
+
+                let order_amounts = amounts_from_book_exact_out(
+                    book,
+                    side,
+                    target_out,
+                    &self.market,
+                    self.fresh_oracle_price(),
+                    self.timestamp,
+                )?;
+
+                (
+                    div_ceil(
+                        order_amounts.total_base_taken_native,
+                        self.market.base_lot_size,
+                    ),
+                    div_ceil(
+                        order_amounts.total_quote_taken_native,
+                        self.market.quote_lot_size,
+                    ),
+                )
+            }
         };
 
+        // A take order emits one fill event per maker it crosses; if that would overrun the
+        // event heap's remaining capacity, shrink the order to the portion the heap can record
+        // instead of sizing an instruction that reverts or partially fills on-chain. This uses
+        // the same capping method as `quote`, so the two can't disagree on the executable size.
+        let (max_base_lots, max_quote_lots_including_fees, _capacity_capped) = self
+            .cap_lots_to_event_heap_capacity(side, max_base_lots, max_quote_lots_including_fees)?;
+
         let bids_ref = RefCell::new(self.bids);
         let asks_ref = RefCell::new(self.asks);
         let book = Orderbook {
@@ -251,7 +862,7 @@ impl Amm for OpenBookMarket {
             max_base_lots,
             max_quote_lots_including_fees,
             &self.market,
-            self.oracle_price,
+            self.fresh_oracle_price(),
             self.timestamp,
         )?;
 
@@ -285,13 +896,110 @@ mod test {
     use std::str::FromStr;
 
     #[test]
-    // TODO replace std::env by mainnet market after audit deploy
-    fn test_jupiter_local() -> Result<()> {
+    fn test_merge_level_sums_non_adjacent_same_price_lots() {
+        let mut levels = Vec::new();
+
+        // A fixed-price order at price_lots 150...
+        merge_level(&mut levels, 150, 10.0);
+        // ...then an order at a different price landing in between...
+        merge_level(&mut levels, 140, 3.0);
+        // ...then an oracle-pegged order that also resolves to price_lots 150, non-adjacent to
+        // the first.
+        merge_level(&mut levels, 150, 7.0);
+
+        assert_eq!(levels, vec![(150, 17.0), (140, 3.0)]);
+    }
+
+    fn test_market(base_lot_size: i64, quote_lot_size: i64, taker_fee: f64) -> Market {
+        Market {
+            base_lot_size,
+            quote_lot_size,
+            taker_fee: I80F48::from_num(taker_fee),
+            ..Market::zeroed()
+        }
+    }
+
+    #[test]
+    fn test_walk_exact_out_bid_rounds_final_level_up() {
+        let market = test_market(2, 1, 0.0);
+
+        // Two resting asks: (price_lots, quantity in base lots).
+        let levels = vec![(5, 3), (8, 5)];
+
+        // Asking for 7 native base units forces a partial final level; at a base lot size of 2
+        // the only way to cover it is to round up to 8, never short of the target.
+        let amounts = walk_exact_out_bid(levels.into_iter(), 7, &market);
+
+        assert_eq!(amounts.total_base_taken_native, 8);
+        assert_eq!(amounts.total_quote_taken_native, 23);
+        assert_eq!(amounts.fee, 0);
+        assert!(!amounts.not_enough_liquidity);
+    }
+
+    #[test]
+    fn test_walk_exact_out_bid_flags_not_enough_liquidity() {
+        let market = test_market(1, 1, 0.0);
+        let levels = vec![(1, 2)];
+
+        let amounts = walk_exact_out_bid(levels.into_iter(), 5, &market);
+
+        assert_eq!(amounts.total_base_taken_native, 2);
+        assert_eq!(amounts.total_quote_taken_native, 2);
+        assert_eq!(amounts.fee, 0);
+        assert!(amounts.not_enough_liquidity);
+    }
+
+    #[test]
+    fn test_walk_exact_out_ask_grosses_up_and_nets_the_fee() {
+        // 0.125 (an exact power of two) so the fixed-point fee math has no rounding slack to
+        // second-guess in this test's expected numbers.
+        let market = test_market(1, 1, 0.125);
+
+        // Two resting bids the taker sells into.
+        let levels = vec![(40, 1), (48, 1)];
+
+        // Wants 70 native quote units out net of a 12.5% fee, so the walk must cover a gross 80.
+        let amounts = walk_exact_out_ask(levels.into_iter(), 70, &market);
+
+        assert_eq!(amounts.total_base_taken_native, 2);
+        // matched - fee, per amounts_from_book's Ask convention: 88 matched - 11 fee.
+        assert_eq!(amounts.total_quote_taken_native, 77);
+        assert_eq!(amounts.fee, 11);
+        assert!(!amounts.not_enough_liquidity);
+
+        // `out = total_quote_taken_native + fee` recovers the gross matched amount, which is
+        // never short of what the taker asked for net of the fee.
+        assert!(amounts.total_quote_taken_native + amounts.fee >= 70);
+    }
+
+    #[test]
+    fn test_crossed_order_count_counts_orders_not_accounts() {
+        // Three resting orders at increasing prices, as if two of them belonged to the same
+        // maker account: `remaining_accounts_to_crank` would dedupe these to fewer accounts, but
+        // each still crosses as its own fill event.
+        let levels = vec![(1, 4), (2, 4), (3, 4)];
+
+        // Enough base and quote lots to fully cross all three levels.
+        assert_eq!(crossed_order_count(levels.into_iter(), 12, 100), 3);
+    }
+
+    #[test]
+    fn test_crossed_order_count_stops_at_the_quote_cap() {
+        let levels = vec![(1, 10), (1, 10), (1, 10)];
+
+        // 15 quote lots only covers the first order in full and part of the second, so the walk
+        // should still count that second, partially-matched order but never reach the third.
+        assert_eq!(crossed_order_count(levels.into_iter(), 100, 15), 2);
+    }
+
+    /// Loads the market named by `MARKET_PUBKEY` from a local validator, or `None` if the env
+    /// var isn't set, so tests that need real book data can skip themselves in CI the same way.
+    fn local_market() -> Result<Option<OpenBookMarket>> {
         let market = match std::env::var("MARKET_PUBKEY") {
             Ok(key) => Pubkey::from_str(&key)?,
             Err(_) => {
                 println!("missing MARKET_PUBKEY env with an existing market in the local validator, skipping test");
-                return Ok(());
+                return Ok(None);
             }
         };
 
@@ -315,6 +1023,16 @@ mod test {
 
         openbook.update(&accounts)?;
 
+        Ok(Some(openbook))
+    }
+
+    #[test]
+    // TODO replace std::env by mainnet market after audit deploy
+    fn test_jupiter_local() -> Result<()> {
+        let Some(openbook) = local_market()? else {
+            return Ok(());
+        };
+
         let (base_mint, quote_mint) = {
             let reserves = openbook.get_reserve_mints();
             (reserves[0], reserves[1])
@@ -324,6 +1042,7 @@ mod test {
             in_amount: 80,
             input_mint: base_mint,
             output_mint: quote_mint,
+            swap_mode: SwapMode::ExactIn,
         };
 
         let quote = openbook.quote(&quote_params)?;
@@ -339,4 +1058,43 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_exact_out_round_trips_exact_in() -> Result<()> {
+        let Some(openbook) = local_market()? else {
+            return Ok(());
+        };
+
+        let (base_mint, quote_mint) = {
+            let reserves = openbook.get_reserve_mints();
+            (reserves[0], reserves[1])
+        };
+
+        let exact_in_quote = openbook.quote(&QuoteParams {
+            in_amount: 80,
+            input_mint: base_mint,
+            output_mint: quote_mint,
+            swap_mode: SwapMode::ExactIn,
+        })?;
+
+        let exact_out_quote = openbook.quote(&QuoteParams {
+            in_amount: exact_in_quote.out_amount,
+            input_mint: base_mint,
+            output_mint: quote_mint,
+            swap_mode: SwapMode::ExactOut,
+        })?;
+
+        // Both quotes walk the same book for the same trade, so the amount ExactOut says it
+        // needs in should land within two native units of what ExactIn actually consumed.
+        let in_amount_diff =
+            (exact_out_quote.in_amount as i64 - exact_in_quote.in_amount as i64).abs();
+        assert!(
+            in_amount_diff <= 2,
+            "exact-in in_amount {} vs exact-out round-trip in_amount {}",
+            exact_in_quote.in_amount,
+            exact_out_quote.in_amount
+        );
+
+        Ok(())
+    }
 }